@@ -1,30 +1,28 @@
-#[macro_use(bson, doc)]
-extern crate bson;
-extern crate mongodb;
-extern crate chrono;
-
 use std::result;
-use mongodb::{Client, ThreadedClient};
-use mongodb::cursor::Cursor;
-use mongodb::db::ThreadedDatabase;
-use mongodb::coll::options::{FindOptions, CursorType};
-use chrono::{DateTime, UTC, TimeZone};
+
+use bson::{doc, Bson, Document, Timestamp};
+use chrono::{DateTime, TimeZone, Utc};
+use mongodb::error::Error as MongoError;
+use mongodb::options::{CursorType, FindOptions};
+use mongodb::sync::{Client, Cursor};
+use serde::Deserialize;
 
 #[derive(Debug)]
 pub enum OplogError {
-    MissingField(bson::ValueAccessError),
-    Database(mongodb::Error),
+    Parse(bson::de::Error),
+    Database(MongoError),
+    MissingField(&'static str),
     UnknownOperation(String),
 }
 
-impl From<bson::ValueAccessError> for OplogError {
-    fn from(original: bson::ValueAccessError) -> OplogError {
-        OplogError::MissingField(original)
+impl From<bson::de::Error> for OplogError {
+    fn from(original: bson::de::Error) -> OplogError {
+        OplogError::Parse(original)
     }
 }
 
-impl From<mongodb::Error> for OplogError {
-    fn from(original: mongodb::Error) -> OplogError {
+impl From<MongoError> for OplogError {
+    fn from(original: MongoError) -> OplogError {
         OplogError::Database(original)
     }
 }
@@ -32,101 +30,242 @@ impl From<mongodb::Error> for OplogError {
 type Result<T> = result::Result<T, OplogError>;
 
 pub struct Oplog {
-    cursor: Cursor,
+    cursor: Cursor<Document>,
+    last_timestamp: Option<Timestamp>,
+}
+
+/// Builds an `Oplog` that can resume tailing from a given timestamp and/or
+/// restrict itself to a single namespace, instead of tailing the entirety of
+/// `local.oplog.rs` from wherever the cursor happens to open.
+pub struct OplogBuilder {
+    client: Client,
+    since: Option<Timestamp>,
+    namespace: Option<String>,
+}
+
+impl OplogBuilder {
+    pub fn new(client: Client) -> OplogBuilder {
+        OplogBuilder {
+            client,
+            since: None,
+            namespace: None,
+        }
+    }
+
+    /// Only return entries with `ts` strictly greater than `ts`, so tailing
+    /// can resume exactly where a previous stream left off.
+    pub fn since(mut self, ts: Timestamp) -> OplogBuilder {
+        self.since = Some(ts);
+        self
+    }
+
+    /// Only return entries whose `ns` field matches `namespace`.
+    pub fn filter_namespace<S: Into<String>>(mut self, namespace: S) -> OplogBuilder {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Builds the `{ ts: { $gt: ... }, ns: ... }` filter document for the
+    /// `since`/`filter_namespace` options that have been set so far, pulled
+    /// out of `build` so it can be unit tested without a live `mongod`.
+    fn build_query(&self) -> Option<Document> {
+        let mut query = Document::new();
+        if let Some(ts) = self.since {
+            query.insert("ts", doc! { "$gt": Bson::Timestamp(ts) });
+        }
+        if let Some(ref namespace) = self.namespace {
+            query.insert("ns", Bson::String(namespace.clone()));
+        }
+
+        if query.is_empty() { None } else { Some(query) }
+    }
+
+    pub fn build(self) -> Result<Oplog> {
+        let query = self.build_query();
+        let coll = self.client.database("local").collection("oplog.rs");
+
+        let opts = FindOptions::builder()
+            .cursor_type(CursorType::TailableAwait)
+            .no_cursor_timeout(true)
+            .build();
+
+        Ok(Oplog {
+            cursor: coll.find(query, opts)?,
+            last_timestamp: self.since,
+        })
+    }
 }
 
 #[derive(PartialEq, Debug)]
-pub struct Operation<'a> {
+pub struct Operation {
     id: i64,
-    timestamp: DateTime<UTC>,
-    document: &'a bson::Document,
-    kind: Kind<'a>
+    timestamp: DateTime<Utc>,
+    document: Document,
+    kind: Kind,
 }
 
 #[derive(PartialEq, Debug)]
-pub enum Kind<'a> {
-    Insert { namespace: &'a str },
-    Update,
-    Delete,
-    Command,
+pub enum Kind {
+    Insert { namespace: String },
+    Update { namespace: String, query: Document },
+    Delete { namespace: String },
+    Command { namespace: String },
+    ApplyOps { operations: Vec<Operation> },
     Database,
     Noop,
 }
 
-impl<'a> Operation<'a> {
-    pub fn new(document: &'a bson::Document) -> Result<Operation<'a>> {
-        let op = try!(document.get_str("op"));
+/// The fields common to every oplog entry, deserialized directly off the
+/// BSON document instead of hand-extracted field by field. `h` and `ts` are
+/// optional here because entries nested inside an `applyOps` array may omit
+/// them and inherit the enclosing entry's instead.
+#[derive(Debug, Deserialize)]
+struct Entry {
+    #[serde(default)]
+    h: Option<i64>,
+    #[serde(default)]
+    ts: Option<Timestamp>,
+    op: String,
+    #[serde(default)]
+    ns: String,
+    #[serde(default)]
+    o: Document,
+    o2: Option<Document>,
+}
 
-        match op {
-            "n" => document_to_noop(document),
-            "i" => document_to_insert(document),
-            _ => Err(OplogError::UnknownOperation(op.to_owned())),
-        }
+impl Operation {
+    pub fn new(document: &Document) -> Result<Operation> {
+        parse_entry(document, None, None)
+    }
+}
+
+fn parse_entry(document: &Document, default_h: Option<i64>, default_ts: Option<Timestamp>) -> Result<Operation> {
+    let entry: Entry = bson::from_document(document.clone())?;
+
+    let h = entry.h.or(default_h).ok_or(OplogError::MissingField("h"))?;
+    let ts = entry.ts.or(default_ts).ok_or(OplogError::MissingField("ts"))?;
+
+    if entry.op == "c" {
+        return parse_command(entry.ns, entry.o, h, ts);
     }
 
-    fn new_with_kind<'f>(document: &'f bson::Document, kind: Kind<'f>) -> Result<Operation<'f>> {
-        let h = try!(document.get_i64("h"));
-        let ts = try!(document.get_time_stamp("ts"));
-        let o = try!(document.get_document("o"));
+    let kind = match entry.op.as_str() {
+        "n" => Kind::Noop,
+        "i" => Kind::Insert { namespace: entry.ns },
+        "u" => Kind::Update {
+            namespace: entry.ns,
+            query: entry.o2.ok_or(OplogError::MissingField("o2"))?,
+        },
+        "d" => Kind::Delete { namespace: entry.ns },
+        other => return Err(OplogError::UnknownOperation(other.to_owned())),
+    };
 
-        Ok(Operation {
+    Ok(Operation {
+        id: h,
+        timestamp: timestamp_to_datetime(ts),
+        document: entry.o,
+        kind,
+    })
+}
+
+// `applyOps` batches multiple writes (or an entire transaction) into a
+// single command entry, with each element of `o.applyOps` being a
+// miniature oplog entry of its own.
+fn parse_command(namespace: String, o: Document, h: i64, ts: Timestamp) -> Result<Operation> {
+    if let Ok(apply_ops) = o.get_array("applyOps") {
+        let mut operations = Vec::with_capacity(apply_ops.len());
+
+        for entry in apply_ops {
+            let sub_document = entry.as_document().ok_or_else(|| {
+                OplogError::UnknownOperation("applyOps entry is not a document".to_owned())
+            })?;
+
+            operations.push(parse_entry(sub_document, Some(h), Some(ts))?);
+        }
+
+        return Ok(Operation {
             id: h,
             timestamp: timestamp_to_datetime(ts),
             document: o,
-            kind: kind
-        })
+            kind: Kind::ApplyOps { operations },
+        });
     }
-}
 
-fn document_to_noop(document: &bson::Document) -> Result<Operation> {
-    Operation::new_with_kind(document, Kind::Noop)
+    Ok(Operation {
+        id: h,
+        timestamp: timestamp_to_datetime(ts),
+        document: o,
+        kind: Kind::Command { namespace },
+    })
 }
 
-fn document_to_insert(document: &bson::Document) -> Result<Operation> {
-    let kind = Kind::Insert {
-        namespace: try!(document.get_str("ns"))
-    };
+fn timestamp_to_datetime(ts: Timestamp) -> DateTime<Utc> {
+    // `increment` is an ordinal, not a millisecond count, so clusters doing
+    // >999 writes/sec in the same wall-clock second can push this past the
+    // nanosecond range `DateTime` accepts; clamp instead of panicking on
+    // otherwise-valid entries.
+    let nanoseconds = (ts.increment as i64 * 1_000_000).min(999_999_999) as u32;
 
-    Operation::new_with_kind(document, kind)
+    Utc.timestamp_opt(ts.time as i64, nanoseconds).unwrap()
 }
 
-fn timestamp_to_datetime(timestamp: i64) -> DateTime<UTC> {
-    let seconds = timestamp >> 32;
-    let nanoseconds = ((timestamp & 0xFFFFFFFF) * 1000000) as u32;
-
-    UTC.timestamp(seconds, nanoseconds)
+fn document_timestamp(document: &Document) -> Option<Timestamp> {
+    document.get("ts").and_then(|ts| bson::from_bson(ts.clone()).ok())
 }
 
-impl Iterator for Oplog {
-    type Item = bson::Document;
+impl Oplog {
+    pub fn new(client: Client) -> Result<Oplog> {
+        OplogBuilder::new(client).build()
+    }
 
-    fn next(&mut self) -> Option<bson::Document> {
+    /// The `ts` of the most recently yielded entry, or the `since` timestamp
+    /// the builder was given if nothing has been yielded yet. Callers can
+    /// persist this as a checkpoint and pass it back to `OplogBuilder::since`
+    /// to resume the stream after a restart.
+    pub fn last_timestamp(&self) -> Option<Timestamp> {
+        self.last_timestamp
+    }
+
+    /// Returns the next entry as a raw `bson::Document`, for callers who
+    /// want to do their own parsing. Busy-loops past cursor errors, matching
+    /// the historical behaviour of tailing `local.oplog.rs` directly.
+    pub fn raw(&mut self) -> Option<Document> {
         loop {
-            if let Some(Ok(op)) = self.cursor.next() {
-                return Some(op);
+            if let Some(Ok(document)) = self.cursor.next() {
+                if let Some(ts) = document_timestamp(&document) {
+                    self.last_timestamp = Some(ts);
+                }
+
+                return Some(document);
             }
         }
     }
 }
 
-impl Oplog {
-    pub fn new(client: Client) -> Result<Oplog> {
-        let coll = client.db("local").collection("oplog.rs");
+impl Iterator for Oplog {
+    type Item = Result<Operation>;
 
-        let mut opts = FindOptions::new();
-        opts.cursor_type = CursorType::TailableAwait;
-        opts.no_cursor_timeout = true;
+    fn next(&mut self) -> Option<Result<Operation>> {
+        match self.cursor.next() {
+            None => None,
+            Some(Err(e)) => Some(Err(OplogError::from(e))),
+            Some(Ok(document)) => {
+                if let Some(ts) = document_timestamp(&document) {
+                    self.last_timestamp = Some(ts);
+                }
 
-        Ok(Oplog { cursor: try!(coll.find(None, Some(opts))) })
+                Some(Operation::new(&document))
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bson::Bson;
     use bson::oid::ObjectId;
-    use chrono::{UTC, TimeZone};
+    use bson::{doc, Bson, Timestamp};
+    use chrono::{TimeZone, Utc};
 
     macro_rules! assert_eq_pretty {
         ($left:expr, $right:expr) => {
@@ -147,16 +286,54 @@ mod tests {
         }
     }
 
+    fn ts(time: u32) -> Bson {
+        Bson::Timestamp(Timestamp { time, increment: 0 })
+    }
+
+    #[test]
+    fn operation_timestamp_includes_increment() {
+        let doc = doc! {
+            "ts": Bson::Timestamp(Timestamp { time: 1479419535, increment: 3 }),
+            "h": -2135725856567446411i64,
+            "v": 2,
+            "op": "n",
+            "ns": "",
+            "o": {
+                "msg": "initiating set"
+            }
+        };
+
+        let operation = Operation::new(&doc).unwrap();
+        assert_eq!(operation.timestamp, Utc.timestamp_opt(1479419535, 3_000_000).unwrap());
+    }
+
+    #[test]
+    fn operation_timestamp_clamps_large_increments() {
+        let doc = doc! {
+            "ts": Bson::Timestamp(Timestamp { time: 1479419535, increment: 1500 }),
+            "h": -2135725856567446411i64,
+            "v": 2,
+            "op": "n",
+            "ns": "",
+            "o": {
+                "msg": "initiating set"
+            }
+        };
+
+        let operation = Operation::new(&doc).unwrap();
+        assert_eq!(operation.timestamp, Utc.timestamp_opt(1479419535, 999_999_999).unwrap());
+    }
+
     #[test]
     fn operation_converts_noops() {
-        let ref doc = doc! {
-            "ts" => (Bson::TimeStamp(1479419535 << 32)),
-            "h" => (-2135725856567446411i64),
-            "v" => 2,
-            "op" => "n",
-            "ns" => "",
-            "o" => {
-                "msg" => "initiating set"
+        let doc = doc! {
+            "ts": ts(1479419535),
+            "h": -2135725856567446411i64,
+            "v": 2,
+            "op": "n",
+            "ns": "",
+            "o": {
+                "msg": "initiating set"
             }
         };
 
@@ -165,8 +342,8 @@ mod tests {
             operation,
             Operation {
                 id: -2135725856567446411i64,
-                timestamp: UTC.timestamp(1479419535, 0),
-                document: &doc! { "msg" => "initiating set" },
+                timestamp: Utc.timestamp_opt(1479419535, 0).unwrap(),
+                document: doc! { "msg": "initiating set" },
                 kind: Kind::Noop,
             }
         );
@@ -175,47 +352,227 @@ mod tests {
     #[test]
     fn operation_converts_inserts() {
         let oid = ObjectId::with_string("583050b26813716e505a5bf2").unwrap();
-        let ref doc = doc! {
-            "ts" => (Bson::TimeStamp(1479561394 << 32)),
-            "h" => (-1742072865587022793i64),
-            "v" => 2,
-            "op" => "i",
-            "ns" => "foo.bar",
-            "o" => {
-                "_id" => (Bson::ObjectId(oid.clone())),
-                "foo" => "bar"
+        let doc = doc! {
+            "ts": ts(1479561394),
+            "h": -1742072865587022793i64,
+            "v": 2,
+            "op": "i",
+            "ns": "foo.bar",
+            "o": {
+                "_id": oid.clone(),
+                "foo": "bar"
             }
         };
-        let operation = Operation::new(doc).unwrap();
+        let operation = Operation::new(&doc).unwrap();
 
         assert_eq_pretty!(
             operation,
             Operation {
                 id: -1742072865587022793i64,
-                timestamp: UTC.timestamp(1479561394, 0),
-                document: &doc! {
-                    "_id" => (Bson::ObjectId(oid)),
-                    "foo" => "bar"
+                timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                document: doc! {
+                    "_id": oid,
+                    "foo": "bar"
                 },
-                kind: Kind::Insert { namespace: "foo.bar" }
+                kind: Kind::Insert { namespace: "foo.bar".to_owned() }
             }
         );
+    }
 
-        // Compare these
-        // assert_eq!(
-        //     operation,
-        //     Operation::Database {
-        //         id: 2013,
-        //         namespace: "hello",
-        //     }
-        // );
-
-        // assert_eq_pretty!(
-        //     operation,
-        //     Operation::Database {
-        //         id: 2013,
-        //         namespace: "hello",
-        //     }
-        // );
+    #[test]
+    fn operation_converts_updates() {
+        let oid = ObjectId::with_string("583050b26813716e505a5bf2").unwrap();
+        let doc = doc! {
+            "ts": ts(1479561394),
+            "h": -1742072865587022793i64,
+            "v": 2,
+            "op": "u",
+            "ns": "foo.bar",
+            "o2": {
+                "_id": oid.clone()
+            },
+            "o": {
+                "$set": {
+                    "foo": "baz"
+                }
+            }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        assert_eq_pretty!(
+            operation,
+            Operation {
+                id: -1742072865587022793i64,
+                timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                document: doc! {
+                    "$set": {
+                        "foo": "baz"
+                    }
+                },
+                kind: Kind::Update {
+                    namespace: "foo.bar".to_owned(),
+                    query: doc! { "_id": oid },
+                }
+            }
+        );
+    }
+
+    #[test]
+    fn operation_converts_deletes() {
+        let oid = ObjectId::with_string("583050b26813716e505a5bf2").unwrap();
+        let doc = doc! {
+            "ts": ts(1479561394),
+            "h": -1742072865587022793i64,
+            "v": 2,
+            "op": "d",
+            "ns": "foo.bar",
+            "o": {
+                "_id": oid.clone()
+            }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        assert_eq_pretty!(
+            operation,
+            Operation {
+                id: -1742072865587022793i64,
+                timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                document: doc! { "_id": oid },
+                kind: Kind::Delete { namespace: "foo.bar".to_owned() }
+            }
+        );
+    }
+
+    #[test]
+    fn operation_converts_commands() {
+        let doc = doc! {
+            "ts": ts(1479561394),
+            "h": -1742072865587022793i64,
+            "v": 2,
+            "op": "c",
+            "ns": "foo.$cmd",
+            "o": {
+                "create": "bar"
+            }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        assert_eq_pretty!(
+            operation,
+            Operation {
+                id: -1742072865587022793i64,
+                timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                document: doc! { "create": "bar" },
+                kind: Kind::Command { namespace: "foo.$cmd".to_owned() }
+            }
+        );
+    }
+
+    #[test]
+    fn operation_converts_apply_ops() {
+        let oid = ObjectId::with_string("583050b26813716e505a5bf2").unwrap();
+        let doc = doc! {
+            "ts": ts(1479561394),
+            "h": -1742072865587022793i64,
+            "v": 2,
+            "op": "c",
+            "ns": "admin.$cmd",
+            "o": {
+                "applyOps": [
+                    {
+                        "op": "i",
+                        "ns": "foo.bar",
+                        "o": {
+                            "_id": oid.clone(),
+                            "foo": "bar"
+                        }
+                    },
+                    {
+                        "op": "d",
+                        "ns": "foo.baz",
+                        "o": {
+                            "_id": oid.clone()
+                        }
+                    }
+                ]
+            }
+        };
+        let operation = Operation::new(&doc).unwrap();
+
+        let operations = match operation.kind {
+            Kind::ApplyOps { operations } => operations,
+            other => panic!("expected Kind::ApplyOps, got {:?}", other),
+        };
+
+        assert_eq!(operations.len(), 2);
+
+        assert_eq_pretty!(
+            operations[0],
+            Operation {
+                id: -1742072865587022793i64,
+                timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                document: doc! {
+                    "_id": oid.clone(),
+                    "foo": "bar"
+                },
+                kind: Kind::Insert { namespace: "foo.bar".to_owned() }
+            }
+        );
+
+        assert_eq_pretty!(
+            operations[1],
+            Operation {
+                id: -1742072865587022793i64,
+                timestamp: Utc.timestamp_opt(1479561394, 0).unwrap(),
+                document: doc! { "_id": oid },
+                kind: Kind::Delete { namespace: "foo.baz".to_owned() }
+            }
+        );
+    }
+
+    fn test_client() -> Client {
+        // Parsing a URI doesn't connect to anything, so this is safe to use
+        // in unit tests that only exercise `OplogBuilder`'s query building.
+        Client::with_uri_str("mongodb://localhost:27017").unwrap()
+    }
+
+    #[test]
+    fn build_query_is_none_without_filters() {
+        let builder = OplogBuilder::new(test_client());
+        assert_eq!(builder.build_query(), None);
+    }
+
+    #[test]
+    fn build_query_filters_by_since() {
+        let since = Timestamp { time: 1479561394, increment: 1 };
+        let builder = OplogBuilder::new(test_client()).since(since);
+
+        assert_eq_pretty!(
+            builder.build_query().unwrap(),
+            doc! { "ts": { "$gt": Bson::Timestamp(since) } }
+        );
+    }
+
+    #[test]
+    fn build_query_filters_by_namespace() {
+        let builder = OplogBuilder::new(test_client()).filter_namespace("foo.bar");
+
+        assert_eq_pretty!(builder.build_query().unwrap(), doc! { "ns": "foo.bar" });
+    }
+
+    #[test]
+    fn build_query_combines_since_and_namespace() {
+        let since = Timestamp { time: 1479561394, increment: 1 };
+        let builder = OplogBuilder::new(test_client())
+            .since(since)
+            .filter_namespace("foo.bar");
+
+        assert_eq_pretty!(
+            builder.build_query().unwrap(),
+            doc! {
+                "ts": { "$gt": Bson::Timestamp(since) },
+                "ns": "foo.bar"
+            }
+        );
     }
 }